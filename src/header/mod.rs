@@ -1,27 +1,73 @@
-use curve25519_dalek::montgomery::MontgomeryPoint;
-use curve25519_dalek::scalar::Scalar;
-
 use crate::constants::HEADER_INTEGRITY_MAC_SIZE;
-use crate::crypto::{compute_keyed_hmac, PublicKey, SharedKey};
+use crate::header::crypto::{DefaultSphinxCrypto, GroupElement, Kdf, Mac, SphinxCrypto};
 use crate::header::delays::Delay;
 use crate::header::filler::Filler;
 use crate::header::keys::{PayloadKey, StreamCipherKey};
+use crate::header::replay::{ReplayDetector, ReplayTag};
 use crate::header::routing::nodes::{EncryptedRoutingInformation, ParsedRawRoutingInformation};
 use crate::header::routing::{EncapsulatedRoutingInformation, ENCRYPTED_ROUTING_INFO_SIZE};
 use crate::route::{Destination, DestinationAddressBytes, Node, NodeAddressBytes, SURBIdentifier};
 use crate::{crypto, ProcessingError};
 
+pub mod crypto;
 pub mod delays;
 pub mod filler;
 pub mod keys;
 pub mod mac;
+pub mod replay;
+pub mod rotation;
 pub mod routing;
+pub mod surb;
+
+/// Convenience alias for the scalar type that pairs with `C`'s group
+/// element, e.g. what a client's `initial_secret` or a mix's
+/// `node_secret_key` is.
+pub type ScalarOf<C> = <<C as SphinxCrypto>::GroupElement as GroupElement>::Scalar;
+
+/// The packet format version `SphinxHeader::new` stamps into every header it
+/// builds. Bump this whenever the routing-info encoding changes in a way
+/// that would make an old header misparse as a new one (or vice versa) -
+/// `SphinxHeader::from_bytes` rejects anything that doesn't match.
+pub const VERSION: u8 = 1;
+
+// 32 represents size of a MontgomeryPoint on Curve25519; kept for the
+// default curve25519 + AES-CTR + HMAC backend, and counts the version byte.
+// Generic callers should use `SphinxVersion::SIZE + C::HEADER_SIZE` instead.
+pub const HEADER_SIZE: usize =
+    SphinxVersion::SIZE + 32 + HEADER_INTEGRITY_MAC_SIZE + ENCRYPTED_ROUTING_INFO_SIZE;
+
+/// A packet format version. `SphinxHeader::from_bytes` reads and validates
+/// this before touching `shared_secret` or the routing info, so a node can
+/// tell a packet built for a format it doesn't implement apart from one
+/// that's merely corrupt, and reject it with
+/// `ProcessingError::UnsupportedVersion` instead of misparsing it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SphinxVersion(u8);
+
+impl SphinxVersion {
+    pub const SIZE: usize = 1;
+
+    /// The version stamped by this build of `SphinxHeader::new`.
+    pub fn current() -> Self {
+        SphinxVersion(VERSION)
+    }
+
+    pub fn is_supported(self) -> bool {
+        self.0 == VERSION
+    }
+
+    pub fn get_value(self) -> u8 {
+        self.0
+    }
 
-// 32 represents size of a MontgomeryPoint on Curve25519
-pub const HEADER_SIZE: usize = 32 + HEADER_INTEGRITY_MAC_SIZE + ENCRYPTED_ROUTING_INFO_SIZE;
+    pub fn from_byte(byte: u8) -> Self {
+        SphinxVersion(byte)
+    }
+}
 
-pub struct SphinxHeader {
-    pub shared_secret: crypto::SharedSecret,
+pub struct SphinxHeader<C: SphinxCrypto = DefaultSphinxCrypto> {
+    pub version: SphinxVersion,
+    pub shared_secret: C::GroupElement,
     pub routing_info: EncapsulatedRoutingInformation,
 }
 
@@ -30,23 +76,30 @@ pub enum SphinxUnwrapError {
     IntegrityMacError,
     RoutingFlagNotRecognized,
     ProcessingHeaderError,
+    ReplayDetected,
 }
 
-pub enum ProcessedHeader {
-    ProcessedHeaderForwardHop(SphinxHeader, NodeAddressBytes, Delay, PayloadKey),
-    ProcessedHeaderFinalHop(DestinationAddressBytes, SURBIdentifier, PayloadKey),
+pub enum ProcessedHeader<C: SphinxCrypto = DefaultSphinxCrypto> {
+    ProcessedHeaderForwardHop(
+        SphinxVersion,
+        SphinxHeader<C>,
+        NodeAddressBytes,
+        Delay,
+        PayloadKey,
+    ),
+    ProcessedHeaderFinalHop(SphinxVersion, DestinationAddressBytes, SURBIdentifier, PayloadKey),
 }
 
-impl SphinxHeader {
+impl<C: SphinxCrypto> SphinxHeader<C> {
     // needs client's secret key, how should we inject this?
-    // needs to deal with SURBs too at some point
+    // SURBs are built on top of this via `surb::SURB::new`
     pub fn new(
-        initial_secret: Scalar,
-        route: &[Node],
+        initial_secret: ScalarOf<C>,
+        route: &[Node<C>],
         delays: &[Delay],
         destination: &Destination,
     ) -> (Self, Vec<PayloadKey>) {
-        let key_material = keys::KeyMaterial::derive(route, initial_secret);
+        let key_material = keys::KeyMaterial::<C>::derive(route, initial_secret);
         let filler_string = Filler::new(&key_material.routing_keys[..route.len() - 1]);
         let routing_info = routing::EncapsulatedRoutingInformation::new(
             route,
@@ -59,6 +112,7 @@ impl SphinxHeader {
         // encapsulate header.routing information, compute MACs
         (
             SphinxHeader {
+                version: SphinxVersion::current(),
                 shared_secret: key_material.initial_shared_secret,
                 routing_info,
             },
@@ -81,18 +135,77 @@ impl SphinxHeader {
             .parse()
     }
 
-    pub fn process(self, node_secret_key: Scalar) -> Result<ProcessedHeader, SphinxUnwrapError> {
+    /// Unwraps one layer of the header, returning the `shared_key`-derived
+    /// `ReplayTag` for this hop alongside the result so the caller can feed
+    /// it to a `ReplayDetector` of its choosing - see
+    /// `process_with_replay_detection` for the common case of checking one
+    /// inline.
+    pub fn process(
+        self,
+        node_secret_key: ScalarOf<C>,
+    ) -> Result<(ProcessedHeader<C>, ReplayTag), SphinxUnwrapError> {
+        let shared_secret = self.shared_secret;
+        let shared_key = keys::KeyMaterial::<C>::compute_shared_key(shared_secret, &node_secret_key);
+        self.unwrap_with_shared_key(shared_key)
+    }
+
+    /// Like `process`, but then checks the derived `ReplayTag` against
+    /// `detector` and fails with `SphinxUnwrapError::ReplayDetected` instead
+    /// of returning the unwrapped header of a packet this mix has already
+    /// processed.
+    pub fn process_with_replay_detection(
+        self,
+        node_secret_key: ScalarOf<C>,
+        detector: &mut impl ReplayDetector,
+    ) -> Result<ProcessedHeader<C>, SphinxUnwrapError> {
+        let (processed_header, replay_tag) = self.process(node_secret_key)?;
+        if detector.check_and_insert(replay_tag) {
+            return Err(SphinxUnwrapError::ReplayDetected);
+        }
+        Ok(processed_header)
+    }
+
+    /// Like `process`, but tries every secret key `rotation` still accepts,
+    /// newest epoch first, instead of a single fixed `node_secret_key`. This
+    /// lets a node rotate its key without dropping packets that were built
+    /// against the previous epoch's key and are still in flight.
+    pub fn process_with_rotation(
+        self,
+        rotation: &rotation::KeyRotation<C>,
+    ) -> Result<(ProcessedHeader<C>, ReplayTag), SphinxUnwrapError> {
         let shared_secret = self.shared_secret;
-        let shared_key = keys::KeyMaterial::compute_shared_key(shared_secret, &node_secret_key);
-        let routing_keys = keys::RoutingKeys::derive(shared_key);
+        let shared_key = rotation
+            .secret_keys_newest_first()
+            .map(|node_secret_key| {
+                keys::KeyMaterial::<C>::compute_shared_key(shared_secret, node_secret_key)
+            })
+            .find(|shared_key| self.mac_is_valid(*shared_key))
+            .ok_or(SphinxUnwrapError::IntegrityMacError)?;
+        self.unwrap_with_shared_key(shared_key)
+    }
 
-        if !self.routing_info.integrity_mac.verify(
-            routing_keys.header_integrity_hmac_key,
+    fn mac_is_valid(&self, shared_key: crypto::SharedKey) -> bool {
+        let routing_keys = <C::Kdf as Kdf>::derive(shared_key);
+        <C::Mac as Mac>::verify(
+            &routing_keys.header_integrity_hmac_key,
             self.routing_info.enc_routing_information.get_value_ref(),
-        ) {
+            &self.routing_info.integrity_mac.get_value(),
+        )
+    }
+
+    fn unwrap_with_shared_key(
+        self,
+        shared_key: crypto::SharedKey,
+    ) -> Result<(ProcessedHeader<C>, ReplayTag), SphinxUnwrapError> {
+        if !self.mac_is_valid(shared_key) {
             return Err(SphinxUnwrapError::IntegrityMacError);
         }
 
+        let version = self.version;
+        let shared_secret = self.shared_secret;
+        let replay_tag = ReplayTag::derive::<C>(&shared_key);
+        let routing_keys = <C::Kdf as Kdf>::derive(shared_key);
+
         // blind the shared_secret in the header
         let new_shared_secret = self.blind_the_shared_secret(shared_secret, shared_key);
 
@@ -101,75 +214,85 @@ impl SphinxHeader {
             routing_keys.stream_cipher_key,
         )
         .unwrap();
-        match unwrapped_routing_information {
+        let processed_header = match unwrapped_routing_information {
             ParsedRawRoutingInformation::ForwardHopRoutingInformation(
                 next_hop_address,
                 delay,
                 new_encapsulated_routing_info,
-            ) => Ok(ProcessedHeader::ProcessedHeaderForwardHop(
+            ) => ProcessedHeader::ProcessedHeaderForwardHop(
+                version,
                 SphinxHeader {
+                    version,
                     shared_secret: new_shared_secret,
                     routing_info: new_encapsulated_routing_info,
                 },
                 next_hop_address,
                 delay,
                 routing_keys.payload_key,
-            )),
+            ),
             ParsedRawRoutingInformation::FinalHopRoutingInformation(
                 destination_address,
                 identifier,
-            ) => Ok(ProcessedHeader::ProcessedHeaderFinalHop(
+            ) => ProcessedHeader::ProcessedHeaderFinalHop(
+                version,
                 destination_address,
                 identifier,
                 routing_keys.payload_key,
-            )),
-            _ => Err(SphinxUnwrapError::ProcessingHeaderError),
-        }
+            ),
+            _ => return Err(SphinxUnwrapError::ProcessingHeaderError),
+        };
+        Ok((processed_header, replay_tag))
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.shared_secret
-            .as_bytes()
-            .iter()
-            .cloned()
+        std::iter::once(self.version.get_value())
+            .chain(self.shared_secret.to_bytes())
             .chain(self.routing_info.to_bytes())
             .collect()
     }
 
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ProcessingError> {
-        if bytes.len() != HEADER_SIZE {
+        let total_size = SphinxVersion::SIZE + C::HEADER_SIZE;
+        if bytes.len() != total_size {
             return Err(ProcessingError::InvalidHeaderLengthError);
         }
 
-        let mut shared_secret_bytes = [0u8; 32];
-        // first 32 bytes represent the shared secret
-        shared_secret_bytes.copy_from_slice(&bytes[..32]);
+        // the first byte is the format version - reject anything we don't
+        // implement before touching the shared secret or routing info, since
+        // we have no guarantee those are even laid out the same way
+        let version = SphinxVersion::from_byte(bytes[0]);
+        if !version.is_supported() {
+            return Err(ProcessingError::UnsupportedVersion(version.get_value()));
+        }
+
+        let shared_secret_start = SphinxVersion::SIZE;
+        let shared_secret_end = shared_secret_start + C::GROUP_ELEMENT_SIZE;
+        // next `GROUP_ELEMENT_SIZE` bytes represent the shared secret
+        let shared_secret = C::GroupElement::from_bytes(&bytes[shared_secret_start..shared_secret_end]);
 
         // the rest are for the encapsulated routing info
-        let encapsulated_routing_info_bytes = bytes[32..HEADER_SIZE].to_vec();
+        let encapsulated_routing_info_bytes = bytes[shared_secret_end..total_size].to_vec();
 
         let routing_info =
             EncapsulatedRoutingInformation::from_bytes(encapsulated_routing_info_bytes)?;
 
         Ok(SphinxHeader {
-            shared_secret: MontgomeryPoint(shared_secret_bytes),
+            version,
+            shared_secret,
             routing_info,
         })
     }
 
     fn blind_the_shared_secret(
         &self,
-        shared_secret: PublicKey,
-        shared_key: SharedKey,
-    ) -> PublicKey {
-        let hmac_full = compute_keyed_hmac(
-            shared_secret.to_bytes().to_vec(),
-            &shared_key.to_bytes().to_vec(),
-        );
+        shared_secret: C::GroupElement,
+        shared_key: crypto::SharedKey,
+    ) -> C::GroupElement {
+        let hmac_full = <C::Mac as Mac>::compute(&shared_key, &shared_secret.to_bytes());
         let mut hmac = [0u8; 32];
         hmac.copy_from_slice(&hmac_full[..32]);
-        let blinding_factor = Scalar::from_bytes_mod_order(hmac);
-        shared_secret * blinding_factor
+        let blinding_factor = C::GroupElement::scalar_from_bytes_mod_order(hmac);
+        shared_secret.blind(&blinding_factor)
     }
 }
 
@@ -183,20 +306,11 @@ mod create_and_process_sphinx_packet_header {
     #[test]
     fn it_returns_correct_routing_information_at_each_hop_for_route_of_3_mixnodes() {
         let (node1_sk, node1_pk) = crypto::keygen();
-        let node1 = Node {
-            address: [5u8; NODE_ADDRESS_LENGTH],
-            pub_key: node1_pk,
-        };
+        let node1: Node = Node::new(NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]), node1_pk);
         let (node2_sk, node2_pk) = crypto::keygen();
-        let node2 = Node {
-            address: [4u8; NODE_ADDRESS_LENGTH],
-            pub_key: node2_pk,
-        };
+        let node2: Node = Node::new(NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]), node2_pk);
         let (node3_sk, node3_pk) = crypto::keygen();
-        let node3 = Node {
-            address: [2u8; NODE_ADDRESS_LENGTH],
-            pub_key: node3_pk,
-        };
+        let node3: Node = Node::new(NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]), node3_pk);
         let route = [node1, node2, node3];
         let destination = destination_fixture();
         let initial_secret = crypto::generate_secret();
@@ -204,8 +318,16 @@ mod create_and_process_sphinx_packet_header {
         let (sphinx_header, _) = SphinxHeader::new(initial_secret, &route, &delays, &destination);
 
         //let (new_header, next_hop_address, _) = sphinx_header.process(node1_sk).unwrap();
-        let new_header = match sphinx_header.process(node1_sk).unwrap() {
-            ProcessedHeader::ProcessedHeaderForwardHop(new_header, next_hop_address, delay, _) => {
+        let (processed, _) = sphinx_header.process(node1_sk).unwrap();
+        let new_header = match processed {
+            ProcessedHeader::ProcessedHeaderForwardHop(
+                version,
+                new_header,
+                next_hop_address,
+                delay,
+                _,
+            ) => {
+                assert_eq!(SphinxVersion::current(), version);
                 assert_eq!([4u8; NODE_ADDRESS_LENGTH], next_hop_address);
                 assert_eq!(delays[0].get_value(), delay.get_value());
                 new_header
@@ -213,16 +335,26 @@ mod create_and_process_sphinx_packet_header {
             _ => panic!(),
         };
 
-        let new_header2 = match new_header.process(node2_sk).unwrap() {
-            ProcessedHeader::ProcessedHeaderForwardHop(new_header, next_hop_address, delay, _) => {
+        let (processed2, _) = new_header.process(node2_sk).unwrap();
+        let new_header2 = match processed2 {
+            ProcessedHeader::ProcessedHeaderForwardHop(
+                version,
+                new_header,
+                next_hop_address,
+                delay,
+                _,
+            ) => {
+                assert_eq!(SphinxVersion::current(), version);
                 assert_eq!([2u8; NODE_ADDRESS_LENGTH], next_hop_address);
                 assert_eq!(delays[1].get_value(), delay.get_value());
                 new_header
             }
             _ => panic!(),
         };
-        match new_header2.process(node3_sk).unwrap() {
-            ProcessedHeader::ProcessedHeaderFinalHop(final_destination, identifier, _) => {
+        let (processed3, _) = new_header2.process(node3_sk).unwrap();
+        match processed3 {
+            ProcessedHeader::ProcessedHeaderFinalHop(version, final_destination, identifier, _) => {
+                assert_eq!(SphinxVersion::current(), version);
                 assert_eq!(destination.address, final_destination);
             }
             _ => panic!(),
@@ -315,6 +447,7 @@ mod converting_header_to_bytes {
     fn it_is_possible_to_convert_back_and_forth() {
         let encapsulated_routing_info = encapsulated_routing_information_fixture();
         let header = SphinxHeader {
+            version: SphinxVersion::current(),
             shared_secret: generate_random_curve_point(),
             routing_info: encapsulated_routing_info,
         };
@@ -322,6 +455,7 @@ mod converting_header_to_bytes {
         let header_bytes = header.to_bytes();
         let recovered_header = SphinxHeader::from_bytes(header_bytes).unwrap();
 
+        assert_eq!(header.version, recovered_header.version);
         assert_eq!(header.shared_secret, recovered_header.shared_secret);
         assert_eq!(
             header.routing_info.to_bytes(),
@@ -329,3 +463,114 @@ mod converting_header_to_bytes {
         );
     }
 }
+
+#[cfg(test)]
+mod rejecting_an_unsupported_version {
+    use crate::crypto::generate_random_curve_point;
+    use crate::header::routing::encapsulated_routing_information_fixture;
+
+    use super::*;
+
+    #[test]
+    fn it_returns_an_error_without_touching_the_rest_of_the_header() {
+        let header = SphinxHeader {
+            version: SphinxVersion::from_byte(VERSION + 1),
+            shared_secret: generate_random_curve_point(),
+            routing_info: encapsulated_routing_information_fixture(),
+        };
+
+        match SphinxHeader::from_bytes(header.to_bytes()) {
+            Err(ProcessingError::UnsupportedVersion(version)) => {
+                assert_eq!(VERSION + 1, version)
+            }
+            _ => panic!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod detecting_replayed_packets {
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::header::replay::InMemoryReplayDetector;
+    use crate::route::destination_fixture;
+
+    use super::*;
+
+    #[test]
+    fn it_rejects_the_same_header_processed_twice_by_the_same_node() {
+        let (node_sk, node_pk) = crypto::keygen();
+        let node: Node = Node::new(NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]), node_pk);
+        let route = [node];
+        let destination = destination_fixture();
+        let initial_secret = crypto::generate_secret();
+        let delays = delays::generate(route.len());
+        let (sphinx_header, _) = SphinxHeader::new(initial_secret, &route, &delays, &destination);
+        let replayed_header_bytes = sphinx_header.to_bytes();
+
+        let mut detector = InMemoryReplayDetector::new();
+        sphinx_header
+            .process_with_replay_detection(node_sk, &mut detector)
+            .unwrap();
+
+        let replayed_header = SphinxHeader::from_bytes(replayed_header_bytes).unwrap();
+        match replayed_header.process_with_replay_detection(node_sk, &mut detector) {
+            Err(SphinxUnwrapError::ReplayDetected) => (),
+            _ => panic!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod processing_during_key_rotation {
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::header::rotation::{Epoch, KeyRotation};
+    use crate::route::destination_fixture;
+
+    use super::*;
+
+    fn sphinx_header_for_single_hop_route(
+        node_pk: crypto::PublicKey,
+    ) -> (SphinxHeader, crate::route::Destination) {
+        let node = Node::new(NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]), node_pk);
+        let route = [node];
+        let destination = destination_fixture();
+        let initial_secret = crypto::generate_secret();
+        let delays = delays::generate(route.len());
+        let (sphinx_header, _) = SphinxHeader::new(initial_secret, &route, &delays, &destination);
+        (sphinx_header, destination)
+    }
+
+    #[test]
+    fn it_falls_back_to_a_previous_epochs_key_for_a_packet_built_before_rotation() {
+        let (old_sk, old_pk) = crypto::keygen();
+        let (sphinx_header, destination) = sphinx_header_for_single_hop_route(old_pk);
+
+        let mut rotation = KeyRotation::new(Epoch(0), old_sk);
+        let (new_sk, _) = crypto::keygen();
+        rotation.rotate(Epoch(1), new_sk);
+
+        let (processed, _) = sphinx_header.process_with_rotation(&rotation).unwrap();
+        match processed {
+            ProcessedHeader::ProcessedHeaderFinalHop(_, final_destination, _, _) => {
+                assert_eq!(destination.address, final_destination);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn it_gives_up_once_the_old_epochs_key_has_been_pruned() {
+        let (old_sk, old_pk) = crypto::keygen();
+        let (sphinx_header, _) = sphinx_header_for_single_hop_route(old_pk);
+
+        let mut rotation = KeyRotation::new(Epoch(0), old_sk);
+        let (new_sk, _) = crypto::keygen();
+        rotation.rotate(Epoch(1), new_sk);
+        rotation.prune_expired(0);
+
+        match sphinx_header.process_with_rotation(&rotation) {
+            Err(SphinxUnwrapError::IntegrityMacError) => (),
+            _ => panic!(),
+        }
+    }
+}
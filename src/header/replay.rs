@@ -0,0 +1,143 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::crypto;
+use crate::header::crypto::{Mac, SphinxCrypto};
+
+const REPLAY_TAG_LABEL: &[u8] = b"sphinx-replay-tag";
+
+/// Size in bytes of a `ReplayTag`.
+pub const REPLAY_TAG_SIZE: usize = 16;
+
+/// A compact, per-hop identifier for "have I processed this packet before".
+///
+/// `SphinxHeader::process` derives it as an HMAC of a fixed label under the
+/// freshly computed per-hop `shared_key`, truncated to `REPLAY_TAG_SIZE`
+/// bytes. Because every hop derives its own `shared_key` from the blinded
+/// shared secret, the tag is deterministic across nodes seeing the same
+/// packet at the same hop, but unlinkable across hops or packets.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ReplayTag([u8; REPLAY_TAG_SIZE]);
+
+impl ReplayTag {
+    /// Derives the tag via `C::Mac`, so a `SphinxCrypto` impl that swaps in
+    /// a different MAC gets replay tags computed under that MAC too,
+    /// instead of silently falling back to the default HMAC.
+    pub(crate) fn derive<C: SphinxCrypto>(shared_key: &crypto::SharedKey) -> Self {
+        let hmac_full = <C::Mac as Mac>::compute(shared_key, REPLAY_TAG_LABEL);
+        let mut tag = [0u8; REPLAY_TAG_SIZE];
+        tag.copy_from_slice(&hmac_full[..REPLAY_TAG_SIZE]);
+        ReplayTag(tag)
+    }
+
+    pub fn get_value(&self) -> [u8; REPLAY_TAG_SIZE] {
+        self.0
+    }
+}
+
+/// Tracks which `ReplayTag`s a mix has already seen.
+///
+/// `SphinxHeader::process_with_replay_detection` checks and records a
+/// packet's tag against whatever `ReplayDetector` the caller passes in,
+/// so the in-memory, bounded, and any future (e.g. bloom-filter-backed)
+/// implementations are interchangeable.
+pub trait ReplayDetector {
+    /// Returns `true` if `tag` had already been recorded (i.e. this is a
+    /// replay), and records it as seen otherwise.
+    fn check_and_insert(&mut self, tag: ReplayTag) -> bool;
+}
+
+/// The default `ReplayDetector`: an unbounded set of every tag ever seen.
+///
+/// Exact and simple, but a node's memory use grows with the number of
+/// packets it has ever processed - use `BoundedReplayDetector` on
+/// memory-constrained nodes instead.
+#[derive(Debug, Default)]
+pub struct InMemoryReplayDetector {
+    seen: HashSet<ReplayTag>,
+}
+
+impl InMemoryReplayDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ReplayDetector for InMemoryReplayDetector {
+    fn check_and_insert(&mut self, tag: ReplayTag) -> bool {
+        !self.seen.insert(tag)
+    }
+}
+
+/// A `ReplayDetector` with memory use capped at `capacity` tags.
+///
+/// Tracks insertion order alongside the set and evicts the oldest tag once
+/// `capacity` is exceeded, trading exact replay detection for a bounded
+/// footprint - a node under memory pressure may forget a tag before an
+/// attacker replays it, the same trade-off a bloom filter would make.
+#[derive(Debug)]
+pub struct BoundedReplayDetector {
+    capacity: usize,
+    seen: HashSet<ReplayTag>,
+    insertion_order: VecDeque<ReplayTag>,
+}
+
+impl BoundedReplayDetector {
+    pub fn new(capacity: usize) -> Self {
+        BoundedReplayDetector {
+            capacity,
+            seen: HashSet::with_capacity(capacity),
+            insertion_order: VecDeque::with_capacity(capacity),
+        }
+    }
+}
+
+impl ReplayDetector for BoundedReplayDetector {
+    fn check_and_insert(&mut self, tag: ReplayTag) -> bool {
+        if !self.seen.insert(tag) {
+            return true;
+        }
+
+        self.insertion_order.push_back(tag);
+        if self.insertion_order.len() > self.capacity {
+            if let Some(oldest) = self.insertion_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod in_memory_replay_detector {
+    use super::*;
+
+    #[test]
+    fn it_flags_the_second_occurrence_of_the_same_tag_as_a_replay() {
+        let mut detector = InMemoryReplayDetector::new();
+        let tag = ReplayTag([7u8; REPLAY_TAG_SIZE]);
+
+        assert!(!detector.check_and_insert(tag));
+        assert!(detector.check_and_insert(tag));
+    }
+}
+
+#[cfg(test)]
+mod bounded_replay_detector {
+    use super::*;
+
+    #[test]
+    fn it_forgets_the_oldest_tag_once_capacity_is_exceeded() {
+        let mut detector = BoundedReplayDetector::new(2);
+        let tag1 = ReplayTag([1u8; REPLAY_TAG_SIZE]);
+        let tag2 = ReplayTag([2u8; REPLAY_TAG_SIZE]);
+        let tag3 = ReplayTag([3u8; REPLAY_TAG_SIZE]);
+
+        assert!(!detector.check_and_insert(tag1));
+        assert!(!detector.check_and_insert(tag2));
+        assert!(!detector.check_and_insert(tag3));
+
+        // tag1 was evicted to make room for tag3, so it no longer counts as a replay
+        assert!(!detector.check_and_insert(tag1));
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use crate::header::crypto::{GroupElement, SphinxCrypto};
+use crate::header::ScalarOf;
+
+/// A logical key-rotation period. A mix node advances its `Epoch` by one
+/// every time it publishes a fresh secret key; `KeyRotation` keeps enough of
+/// the trailing epochs around that packets built against an older key are
+/// still accepted while they're in flight.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
+pub struct Epoch(pub u64);
+
+impl Epoch {
+    pub fn next(self) -> Self {
+        Epoch(self.0 + 1)
+    }
+}
+
+/// Holds a mix node's secret keys across epochs so
+/// `SphinxHeader::process_with_rotation` can fall back to a previous
+/// epoch's key for a packet built before the node's most recent rotation,
+/// instead of rejecting it outright.
+///
+/// `rotate` publishes a new epoch's key, which becomes the new
+/// `current_epoch` if it is the most recent epoch seen so far;
+/// `prune_expired` drops epochs older than `current_epoch` by more
+/// than a configurable grace window, so an operator can retire a key once
+/// every packet built against it has either arrived or timed out.
+pub struct KeyRotation<C: SphinxCrypto> {
+    current_epoch: Epoch,
+    secret_keys: BTreeMap<Epoch, ScalarOf<C>>,
+}
+
+impl<C: SphinxCrypto> KeyRotation<C> {
+    pub fn new(current_epoch: Epoch, secret_key: ScalarOf<C>) -> Self {
+        let mut secret_keys = BTreeMap::new();
+        secret_keys.insert(current_epoch, secret_key);
+        KeyRotation {
+            current_epoch,
+            secret_keys,
+        }
+    }
+
+    /// Publishes `secret_key` as the node's key for `epoch`. `epoch` becomes
+    /// the new `current_epoch` if it's the most recent one seen so far.
+    pub fn rotate(&mut self, epoch: Epoch, secret_key: ScalarOf<C>) {
+        self.secret_keys.insert(epoch, secret_key);
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+        }
+    }
+
+    pub fn current_epoch(&self) -> Epoch {
+        self.current_epoch
+    }
+
+    /// Drops every epoch older than `current_epoch` by more than
+    /// `grace_window`. Packets built against a pruned epoch's key are no
+    /// longer accepted by `process_with_rotation`.
+    pub fn prune_expired(&mut self, grace_window: u64) {
+        let oldest_retained = Epoch(self.current_epoch.0.saturating_sub(grace_window));
+        self.secret_keys.retain(|epoch, _| *epoch >= oldest_retained);
+    }
+
+    /// The secret keys this node still accepts, most recent epoch first -
+    /// the order `SphinxHeader::process_with_rotation` tries them in.
+    pub(crate) fn secret_keys_newest_first(&self) -> impl Iterator<Item = &ScalarOf<C>> {
+        self.secret_keys.values().rev()
+    }
+
+    /// The public keys this node can advertise, one per epoch it still
+    /// accepts, oldest epoch first, so clients building routes through it
+    /// can pick the freshest one while older epochs are still listed during
+    /// the grace window.
+    pub fn public_keys(&self) -> Vec<(Epoch, C::GroupElement)> {
+        self.secret_keys
+            .iter()
+            .map(|(epoch, secret_key)| (*epoch, C::GroupElement::from_scalar(secret_key)))
+            .collect()
+    }
+}
@@ -0,0 +1,134 @@
+use crate::crypto;
+use crate::header::crypto::{DefaultSphinxCrypto, SphinxCrypto};
+use crate::header::delays::Delay;
+use crate::header::keys::PayloadKey;
+use crate::header::{ScalarOf, SphinxHeader};
+use crate::route::{Destination, Node, NodeAddressBytes};
+use crate::utils;
+
+/// A Single-Use Reply Block: a header precomputed by a client for a route
+/// leading back to itself, handed out so that a recipient can send a reply
+/// without ever learning who the client is or where it is located.
+///
+/// The creator is the only party that ever sees `payload_keys` - everything
+/// else in this struct is safe to give away.
+pub struct SURB<C: SphinxCrypto = DefaultSphinxCrypto> {
+    sphinx_header: SphinxHeader<C>,
+    first_hop_address: NodeAddressBytes,
+    payload_keys: Vec<PayloadKey>,
+}
+
+impl<C: SphinxCrypto> SURB<C> {
+    /// Precomputes a `SphinxHeader` for `return_route` using the same
+    /// `keys::KeyMaterial::derive` / `Filler` / `EncapsulatedRoutingInformation::new`
+    /// pipeline as `SphinxHeader::new`, so the resulting block is processed by
+    /// mixes exactly like any other packet header.
+    pub fn new(
+        initial_secret: ScalarOf<C>,
+        return_route: &[Node<C>],
+        delays: &[Delay],
+        surb_delivery_destination: &Destination,
+    ) -> Self {
+        let (sphinx_header, payload_keys) =
+            SphinxHeader::new(initial_secret, return_route, delays, surb_delivery_destination);
+
+        SURB {
+            sphinx_header,
+            first_hop_address: return_route[0].address.clone(),
+            payload_keys,
+        }
+    }
+
+    /// The per-hop payload keys, outermost (first hop) first. The creator
+    /// must hang on to these (e.g. alongside the `SURBIdentifier` it expects
+    /// back) so it can recover the plaintext later with
+    /// `unwrap_surb_reply` - `use_surb` consumes the `SURB` before the keys
+    /// would otherwise be reachable.
+    pub fn payload_keys(&self) -> &[PayloadKey] {
+        &self.payload_keys
+    }
+
+    /// Consumes the `SURB` to produce a packet a recipient can send straight
+    /// back into the mix network: the precomputed header, the first hop it
+    /// must be sent to, and `plaintext_payload` onion-encrypted under every
+    /// hop's payload key, innermost (last hop) layer first so that the
+    /// first hop's key ends up as the outermost layer. From here on the
+    /// packet is indistinguishable from one built by `SphinxHeader::new`,
+    /// so mixes route it through the existing
+    /// `process`/`ProcessedHeaderForwardHop` path unchanged, each peeling
+    /// its own layer with the `PayloadKey` that path hands it before
+    /// forwarding the rest of the onion to the next hop.
+    pub fn use_surb(
+        self,
+        plaintext_payload: &[u8],
+    ) -> (SphinxHeader<C>, NodeAddressBytes, Vec<u8>) {
+        let encrypted_payload = self
+            .payload_keys
+            .iter()
+            .rev()
+            .fold(plaintext_payload.to_vec(), |payload, payload_key| {
+                encrypt_payload_layer(&payload, payload_key)
+            });
+
+        (self.sphinx_header, self.first_hop_address, encrypted_payload)
+    }
+}
+
+/// Run by the SURB creator once a reply comes back. Peels the payload
+/// encryption layers in the same order the mixes applied them when
+/// forwarding the packet along the return route, recovering the plaintext
+/// the recipient sent with `SURB::use_surb`.
+pub fn unwrap_surb_reply(stored_payload_keys: &[PayloadKey], received_payload: &[u8]) -> Vec<u8> {
+    stored_payload_keys
+        .iter()
+        .fold(received_payload.to_vec(), |payload, payload_key| {
+            encrypt_payload_layer(&payload, payload_key)
+        })
+}
+
+fn encrypt_payload_layer(payload: &[u8], payload_key: &PayloadKey) -> Vec<u8> {
+    let pseudorandom_bytes = crypto::generate_pseudorandom_bytes(
+        payload_key,
+        &crypto::STREAM_CIPHER_INIT_VECTOR,
+        payload.len(),
+    );
+    utils::bytes::xor(payload, &pseudorandom_bytes)
+}
+
+#[cfg(test)]
+mod creating_and_using_a_surb {
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::route::destination_fixture;
+
+    use super::*;
+
+    #[test]
+    fn it_recovers_the_original_plaintext_for_a_multi_hop_return_route() {
+        let (_, node1_pk) = crypto::keygen();
+        let node1 = Node::new(NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]), node1_pk);
+        let (_, node2_pk) = crypto::keygen();
+        let node2 = Node::new(NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]), node2_pk);
+        let (_, node3_pk) = crypto::keygen();
+        let node3 = Node::new(NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]), node3_pk);
+        let return_route = [node1, node2, node3];
+        let destination = destination_fixture();
+        let initial_secret = crypto::generate_secret();
+        let delays = crate::header::delays::generate(return_route.len());
+
+        let surb: SURB = SURB::new(initial_secret, &return_route, &delays, &destination);
+        let payload_keys = surb.payload_keys().to_vec();
+
+        let plaintext_payload = b"hello, this is a reply".to_vec();
+        let (_, first_hop_address, encrypted_payload) = surb.use_surb(&plaintext_payload);
+
+        assert_eq!(first_hop_address, return_route[0].address);
+        assert_ne!(encrypted_payload, plaintext_payload);
+
+        // peeling only some of the hops' keys should not yet reveal the plaintext
+        let partially_peeled = unwrap_surb_reply(&payload_keys[..1], &encrypted_payload);
+        assert_ne!(partially_peeled, plaintext_payload);
+
+        let recovered_payload = unwrap_surb_reply(&payload_keys, &encrypted_payload);
+        assert_eq!(plaintext_payload, recovered_payload);
+    }
+}
@@ -1,5 +1,8 @@
+use std::marker::PhantomData;
+
 use crate::constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH};
 use crate::crypto;
+use crate::header::crypto::{DefaultSphinxCrypto, SphinxCrypto};
 
 // in paper delta
 #[derive(Clone, Debug, Eq, PartialEq, PartialOrd)]
@@ -87,15 +90,48 @@ impl Destination {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct Node {
+pub struct Node<C: SphinxCrypto = DefaultSphinxCrypto> {
     pub address: NodeAddressBytes,
-    pub pub_key: crypto::PublicKey,
+    pub pub_key: C::GroupElement,
+    _crypto: PhantomData<C>,
+}
+
+// Manual impls instead of `#[derive(..)]`: deriving would require `C: Clone
+// + Debug + PartialEq` even though `C` is only ever used as a marker type -
+// what we actually need is for `C::GroupElement` (which `GroupElement`
+// already bounds on all three) to satisfy them.
+impl<C: SphinxCrypto> Clone for Node<C> {
+    fn clone(&self) -> Self {
+        Node {
+            address: self.address.clone(),
+            pub_key: self.pub_key,
+            _crypto: PhantomData,
+        }
+    }
+}
+
+impl<C: SphinxCrypto> std::fmt::Debug for Node<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Node")
+            .field("address", &self.address)
+            .field("pub_key", &self.pub_key)
+            .finish()
+    }
+}
+
+impl<C: SphinxCrypto> PartialEq for Node<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.address == other.address && self.pub_key == other.pub_key
+    }
 }
 
-impl Node {
-    pub fn new(address: NodeAddressBytes, pub_key: crypto::PublicKey) -> Self {
-        Self { address, pub_key }
+impl<C: SphinxCrypto> Node<C> {
+    pub fn new(address: NodeAddressBytes, pub_key: C::GroupElement) -> Self {
+        Self {
+            address,
+            pub_key,
+            _crypto: PhantomData,
+        }
     }
 }
 
@@ -115,6 +151,7 @@ pub fn random_node() -> Node {
     Node {
         address: NodeAddressBytes([2u8; NODE_ADDRESS_LENGTH]),
         pub_key: crypto::generate_random_curve_point(),
+        _crypto: PhantomData,
     }
 }
 
@@ -0,0 +1,205 @@
+use rand::Rng;
+
+use crate::header::crypto::{DefaultSphinxCrypto, SphinxCrypto};
+use crate::header::delays::{self, Delay};
+use crate::route::Node;
+
+/// A mix this `Topology` knows about, together with the relative weight
+/// `Topology::build_route` uses to bias selection - e.g. proportional to
+/// advertised capacity, so higher-capacity mixes carry more traffic.
+pub struct WeightedNode<C: SphinxCrypto = DefaultSphinxCrypto> {
+    pub node: Node<C>,
+    pub weight: u64,
+}
+
+impl<C: SphinxCrypto> WeightedNode<C> {
+    pub fn new(node: Node<C>, weight: u64) -> Self {
+        WeightedNode { node, weight }
+    }
+}
+
+// Manual impl instead of `#[derive(Debug)]`: deriving would require `C: Debug`
+// even though `Node<C>` (the only field that mentions `C`) already implements
+// `Debug` unconditionally - see `route.rs`'s `Node<C>` for the same reasoning.
+impl<C: SphinxCrypto> std::fmt::Debug for WeightedNode<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeightedNode")
+            .field("node", &self.node)
+            .field("weight", &self.weight)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum TopologyError {
+    /// `build_route` was asked for a route longer than this topology can
+    /// supply distinct nodes for, either because a layer ran out of
+    /// candidates or (for an unlayered topology) the whole pool did.
+    NotEnoughNodes,
+}
+
+/// A client's view of the mix network to build routes through - the role a
+/// router plays in a lightning-style payment network, but for Sphinx
+/// packets instead of payments.
+///
+/// Nodes are grouped into `layers`. A stratified (Loopix-style) mixnet
+/// advertises one layer per hop, and `build_route` draws the hop at
+/// position `i` from layer `i`, so every route crosses the network in the
+/// same stratified order. `new_unlayered` is the degenerate case of a
+/// single layer shared by every hop.
+pub struct Topology<C: SphinxCrypto = DefaultSphinxCrypto> {
+    layers: Vec<Vec<WeightedNode<C>>>,
+}
+
+impl<C: SphinxCrypto> Topology<C> {
+    /// A flat topology with no stratification: every hop of a route is
+    /// drawn from the same pool of nodes.
+    pub fn new_unlayered(nodes: Vec<WeightedNode<C>>) -> Self {
+        Topology {
+            layers: vec![nodes],
+        }
+    }
+
+    /// A stratified topology with one layer per hop a route through it can
+    /// have; `build_route(length, ..)` requires `layers.len() >= length`.
+    /// A single layer is shared by every hop, exactly as with `new_unlayered`.
+    pub fn new_layered(layers: Vec<Vec<WeightedNode<C>>>) -> Self {
+        Topology { layers }
+    }
+
+    /// Builds a `length`-hop route with no repeated node, ready to hand
+    /// straight to `SphinxHeader::new` alongside
+    /// `generate_delays_for_route`.
+    ///
+    /// Hop `i` is drawn from layer `i` of this topology (or, for an
+    /// unlayered topology, from its single pool every time), weighted by
+    /// `WeightedNode::weight` so operators can bias routes toward
+    /// higher-capacity mixes.
+    pub fn build_route(
+        &self,
+        length: usize,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<Node<C>>, TopologyError> {
+        let mut route: Vec<Node<C>> = Vec::with_capacity(length);
+        for hop in 0..length {
+            let candidates: Vec<&WeightedNode<C>> = self
+                .layer_for_hop(hop)
+                .iter()
+                .filter(|weighted| {
+                    !route
+                        .iter()
+                        .any(|picked| picked.address == weighted.node.address)
+                })
+                .collect();
+            let picked = pick_weighted(&candidates, rng).ok_or(TopologyError::NotEnoughNodes)?;
+            route.push(picked.node.clone());
+        }
+        Ok(route)
+    }
+
+    fn layer_for_hop(&self, hop: usize) -> &[WeightedNode<C>] {
+        if self.layers.len() == 1 {
+            &self.layers[0]
+        } else {
+            self.layers.get(hop).map(Vec::as_slice).unwrap_or(&[])
+        }
+    }
+}
+
+fn pick_weighted<'a, C: SphinxCrypto>(
+    candidates: &[&'a WeightedNode<C>],
+    rng: &mut impl Rng,
+) -> Option<&'a WeightedNode<C>> {
+    if candidates.is_empty() {
+        return None;
+    }
+
+    // every node gets at least weight 1, so a `weight: 0` entry is still
+    // selectable rather than silently excluded from the route
+    let total_weight: u64 = candidates.iter().map(|weighted| weighted.weight.max(1)).sum();
+    let mut target = rng.gen_range(0..total_weight);
+    for weighted in candidates {
+        let weight = weighted.weight.max(1);
+        if target < weight {
+            return Some(weighted);
+        }
+        target -= weight;
+    }
+    candidates.last().copied()
+}
+
+/// Generates exponentially-distributed per-hop delays sized for `route`, so
+/// a caller can go from `Topology::build_route` straight to
+/// `SphinxHeader::new` in one step.
+pub fn generate_delays_for_route<C: SphinxCrypto>(route: &[Node<C>]) -> Vec<Delay> {
+    delays::generate(route.len())
+}
+
+#[cfg(test)]
+mod building_a_route {
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::crypto;
+    use crate::route::NodeAddressBytes;
+
+    use super::*;
+
+    fn weighted_node(address_byte: u8, weight: u64) -> WeightedNode {
+        let (_, pub_key) = crypto::keygen();
+        WeightedNode::new(
+            Node::new(NodeAddressBytes::from_bytes([address_byte; NODE_ADDRESS_LENGTH]), pub_key),
+            weight,
+        )
+    }
+
+    #[test]
+    fn it_never_repeats_a_node_in_an_unlayered_topology() {
+        let nodes = (0..4).map(|i| weighted_node(i, 1)).collect();
+        let topology = Topology::new_unlayered(nodes);
+        let mut rng = rand::thread_rng();
+
+        let route = topology.build_route(4, &mut rng).unwrap();
+
+        assert_eq!(4, route.len());
+        for (i, node) in route.iter().enumerate() {
+            assert!(!route[..i].iter().any(|other| other.address == node.address));
+        }
+    }
+
+    #[test]
+    fn it_fails_once_the_pool_is_exhausted() {
+        let nodes = (0..2).map(|i| weighted_node(i, 1)).collect();
+        let topology = Topology::new_unlayered(nodes);
+        let mut rng = rand::thread_rng();
+
+        match topology.build_route(3, &mut rng) {
+            Err(TopologyError::NotEnoughNodes) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn it_draws_each_hop_from_its_own_layer() {
+        let layers = vec![
+            vec![weighted_node(1, 1)],
+            vec![weighted_node(2, 1)],
+            vec![weighted_node(3, 1)],
+        ];
+        let topology = Topology::new_layered(layers);
+        let mut rng = rand::thread_rng();
+
+        let route = topology.build_route(3, &mut rng).unwrap();
+
+        assert_eq!(
+            NodeAddressBytes::from_bytes([1u8; NODE_ADDRESS_LENGTH]),
+            route[0].address
+        );
+        assert_eq!(
+            NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
+            route[1].address
+        );
+        assert_eq!(
+            NodeAddressBytes::from_bytes([3u8; NODE_ADDRESS_LENGTH]),
+            route[2].address
+        );
+    }
+}